@@ -1,3 +1,5 @@
+extern crate regex;
+
 mod parser;
 mod matcher;
 
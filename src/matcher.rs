@@ -6,6 +6,20 @@ pub struct Matcher
     query: parser::Node
 }
 
+/// A single occurrence of a leaf keyword in matched text.
+#[derive(Debug, Clone)]
+pub struct Match
+{
+    /// The byte offset of the start of the match.
+    pub start: usize,
+
+    /// The byte offset of the end of the match (exclusive).
+    pub end: usize,
+
+    /// The keyword that produced this match.
+    pub keyword: String,
+}
+
 impl Matcher
 {
     /// Constructs a new Matcher object from a string.
@@ -20,24 +34,194 @@ impl Matcher
         }
     }
 
+    /// Constructs a new Matcher object from a string, allowing every keyword in the query to
+    /// match text that is within `k` edits (insertions, deletions, substitutions) of the
+    /// keyword. This makes the matcher tolerant of typos in the text being searched.
+    ///
+    /// A `k` of 0 behaves exactly like `from`.
+    ///
+    /// Returns a ParsingError if it fails.
+    pub fn from_fuzzy(s: &str, k: usize) -> parser::Result<Self>
+    {
+        match parser::from_fuzzy(s, k)
+        {
+            Ok(q) => return Ok(Matcher { query: q }),
+            Err(e) => return Err(e),
+        }
+    }
+
     /// Applies the query to the string.
     pub fn query(&self, s: &str) -> bool
     {
-        return match_bquery(&self.query, s)
+        return collect_bquery(&self.query, s).0;
+    }
+
+    /// Applies the query to the string and returns every match produced by a leaf keyword,
+    /// so that callers can highlight hits in `s`.
+    ///
+    /// Only leaves that contribute to an overall match are included: a leaf inside a branch
+    /// of an OR or AND node that didn't match contributes no spans. When matches overlap,
+    /// the longest is returned first.
+    pub fn query_matches(&self, s: &str) -> Vec<Match>
+    {
+        let (_, mut matches) = collect_bquery(&self.query, s);
+        matches.sort_by(|a, b| (b.end - b.start).cmp(&(a.end - a.start)));
+        return matches;
     }
 }
 
-/// Applies `query` to `s`.
-fn match_bquery(query: &parser::Node, s: &str) -> bool
+/// Applies `query` to `s`, returning whether it matched and every span that contributed to
+/// that match.
+fn collect_bquery(query: &parser::Node, s: &str) -> (bool, Vec<Match>)
 {
     use parser::Node::*;
     match query
     {
-        &AND(ref a, ref b) => return match_bquery(&*a, s) && match_bquery(&*b, s),
-        &OR(ref a, ref b) => return match_bquery(&*a, s) || match_bquery(&*b, s),
-        &NOT(ref a) => return !match_bquery(&*a, s),
-        &Leaf(ref keyword, ref jumptable) => return kmp(jumptable, keyword, s),
+        &AND(ref a, ref b) =>
+        {
+            let (matched_a, spans_a) = collect_bquery(&*a, s);
+            let (matched_b, spans_b) = collect_bquery(&*b, s);
+
+            if matched_a && matched_b
+            {
+                let mut spans = spans_a;
+                spans.extend(spans_b);
+                return (true, spans);
+            }
+
+            return (false, Vec::new());
+        },
+
+        &OR(ref a, ref b) =>
+        {
+            let (matched_a, spans_a) = collect_bquery(&*a, s);
+            let (matched_b, spans_b) = collect_bquery(&*b, s);
+
+            if matched_a || matched_b
+            {
+                let mut spans = Vec::new();
+                if matched_a { spans.extend(spans_a); }
+                if matched_b { spans.extend(spans_b); }
+                return (true, spans);
+            }
+
+            return (false, Vec::new());
+        },
+
+        &NOT(ref a) =>
+        {
+            let (matched_a, _) = collect_bquery(&*a, s);
+            return (!matched_a, Vec::new());
+        },
+
+        &Leaf(ref keyword, ref jumptable, k) =>
+        {
+            let occurrences = if k == 0 { kmp(jumptable, keyword, s) } else { fuzzy_match(keyword, s, k) };
+            let matched = !occurrences.is_empty();
+            let spans = occurrences
+                .into_iter()
+                .map(|(start, end)| Match { start: start, end: end, keyword: keyword.clone() })
+                .collect();
+
+            return (matched, spans);
+        },
+
+        &Regex(ref re) =>
+        {
+            let lowered = s.to_lowercase();
+            let matched = re.is_match(&lowered);
+            let spans = re
+                .find_iter(&lowered)
+                .map(|m| Match { start: m.start(), end: m.end(), keyword: re.as_str().to_string() })
+                .collect();
+
+            return (matched, spans);
+        },
+
+        &Near(ref a, ref b, n) =>
+        {
+            let (_, spans_a) = collect_bquery(&*a, s);
+            let (_, spans_b) = collect_bquery(&*b, s);
+
+            // Near needs a concrete span from each side to measure word distance. An operand
+            // that matched without producing one (a NOT, or a compound that matched via
+            // union/negation rather than a leaf occurrence) has no position to measure from, so
+            // the whole Near degrades to a non-match rather than matching unconditionally.
+            if spans_a.is_empty() || spans_b.is_empty()
+            {
+                return (false, Vec::new());
+            }
+
+            let words = tokenise_words(s);
+            let mut matched = false;
+            let mut spans = Vec::new();
+
+            for left in &spans_a
+            {
+                let left_word = match word_index_at(&words, left.start) { Some(i) => i, None => continue };
+
+                for right in &spans_b
+                {
+                    let right_word = match word_index_at(&words, right.start) { Some(i) => i, None => continue };
+                    let distance = if left_word > right_word { left_word - right_word } else { right_word - left_word };
+
+                    if distance <= n
+                    {
+                        matched = true;
+                        spans.push(left.clone());
+                        spans.push(right.clone());
+                    }
+                }
+            }
+
+            if matched { return (true, spans); }
+            return (false, Vec::new());
+        },
+    }
+}
+
+/// Splits `s` into words, returning the byte range of each.
+fn tokenise_words(s: &str) -> Vec<(usize, usize)>
+{
+    let mut words = Vec::new();
+    let mut start: Option<usize> = None;
+
+    for (i, c) in s.char_indices()
+    {
+        if c.is_whitespace()
+        {
+            if let Some(word_start) = start.take()
+            {
+                words.push((word_start, i));
+            }
+        }
+
+        else if start.is_none()
+        {
+            start = Some(i);
+        }
+    }
+
+    if let Some(word_start) = start
+    {
+        words.push((word_start, s.len()));
+    }
+
+    return words;
+}
+
+/// Finds the index of the word containing byte offset `offset`.
+fn word_index_at(words: &Vec<(usize, usize)>, offset: usize) -> Option<usize>
+{
+    for (i, &(start, end)) in words.iter().enumerate()
+    {
+        if offset >= start && offset < end
+        {
+            return Some(i);
+        }
     }
+
+    return None;
 }
 
 /// An implementation of the [Knuth-Morris-Pratt](https://en.wikipedia.org/wiki/Knuth%E2%80%93Morris%E2%80%93Pratt_algorithm) algorithm.
@@ -47,7 +231,9 @@ fn match_bquery(query: &parser::Node, s: &str) -> bool
 /// `table`: The precomputed jump table.
 /// `s1`: The string to search for.
 /// `s2`: The text to search for s1 in.
-fn kmp(table: &Vec<i64>, s1: &str, s2: &str) -> bool
+///
+/// Returns the byte range of every occurrence of `s1` in `s2`.
+fn kmp(table: &Vec<i64>, s1: &str, s2: &str) -> Vec<(usize, usize)>
 {
     let s1 = s1
         .to_string()
@@ -59,6 +245,8 @@ fn kmp(table: &Vec<i64>, s1: &str, s2: &str) -> bool
         .to_lowercase()
         .into_bytes();
 
+    let mut occurrences = Vec::new();
+
     let mut i: i64 = 0;
     let mut j: i64 = -1;
 
@@ -76,13 +264,69 @@ fn kmp(table: &Vec<i64>, s1: &str, s2: &str) -> bool
 
         if j == (s1.len()as i64 -1)
         {
-            return true;
+            let end = (i + 1) as usize;
+            let start = end - s1.len();
+            occurrences.push((start, end));
+            j = table[j as usize];
         }
 
         i += 1;
     }
 
-    return false;
+    return occurrences;
+}
+
+/// Approximate substring matching via a row-based Levenshtein DP.
+///
+/// Scans `s2` byte-by-byte, maintaining the minimum number of edits needed to align some
+/// prefix of `s1` with a suffix of `s2` ending at the current position. `row[0]` is kept at 0
+/// at every step so that a match may begin at any position in `s2`. Records a match every time
+/// the full pattern can be aligned within `k` edits.
+///
+/// Returns a byte range for every position in `s2` at which a match ends. Each range ends just
+/// after that position and starts `s1.len()` bytes before its end (clamped to 0), so for a match
+/// that involves insertions or deletions the range does not cover exactly the matched text.
+fn fuzzy_match(s1: &str, s2: &str, k: usize) -> Vec<(usize, usize)>
+{
+    let s1 = s1
+        .to_string()
+        .to_lowercase()
+        .into_bytes();
+
+    let s2 = s2
+        .to_string()
+        .to_lowercase()
+        .into_bytes();
+
+    let m = s1.len();
+    let mut row: Vec<usize> = (0..=m).collect();
+    let mut occurrences = Vec::new();
+
+    for (i, c) in s2.into_iter().enumerate()
+    {
+        let mut next: Vec<usize> = vec![0; m + 1];
+
+        for j in 1..=m
+        {
+            let sub_cost = if s1[j-1] != c { 1 } else { 0 };
+
+            next[j] = ::std::cmp::min(
+                row[j] + 1,
+                ::std::cmp::min(next[j-1] + 1, row[j-1] + sub_cost)
+            );
+        }
+
+        if next[m] <= k
+        {
+            let end = i + 1;
+            let start = end.saturating_sub(m);
+            occurrences.push((start, end));
+        }
+
+        row = next;
+    }
+
+    return occurrences;
 }
 
 #[cfg(test)]
@@ -118,4 +362,63 @@ mod tests
         print_on_failure(&greeting, "hI THERE");
         print_on_failure(&greeting, "Hi there!");
     }
+
+    #[test]
+    fn fuzzy()
+    {
+        let iphonex = Matcher::from_fuzzy("\"iphone\"", 1).unwrap();
+        print_on_failure(&iphonex, "I love my new iphon!");
+        assert!(!iphonex.query("I love my new ifone!"));
+        assert!(!iphonex.query("I love my new android!"));
+
+        // "ifone" is 2 edits away from "iphone" (substitute p->f, delete h), so it needs a
+        // wider edit budget than "iphon", which is a single deletion away.
+        let iphonex_wide = Matcher::from_fuzzy("\"iphone\"", 2).unwrap();
+        print_on_failure(&iphonex_wide, "I love my new ifone!");
+    }
+
+    #[test]
+    fn query_matches()
+    {
+        let greeting = Matcher::from("\"hello\" & \"there\"").unwrap();
+        let matches = greeting.query_matches("hello there");
+
+        assert_eq!(matches.len(), 2);
+        assert!(greeting.query_matches("hello").is_empty());
+    }
+
+    #[test]
+    fn query_matches_longest_first()
+    {
+        let matcher = Matcher::from("\"car\" | \"carpet\"").unwrap();
+        let matches = matcher.query_matches("a red carpet");
+
+        assert_eq!(matches[0].keyword, "carpet");
+    }
+
+    #[test]
+    fn regex()
+    {
+        let iphonex = Matcher::from("/iph[o0]ne/").unwrap();
+        print_on_failure(&iphonex, "i love my new iph0ne!");
+        print_on_failure(&iphonex, "i love my new iphone!");
+        assert!(!iphonex.query("i love my new android!"));
+    }
+
+    #[test]
+    fn near()
+    {
+        let matcher = Matcher::from("\"quick\" ~3 \"fox\"").unwrap();
+        print_on_failure(&matcher, "the quick brown fox jumps");
+        assert!(!matcher.query("the quick brown lazy old fox jumps"));
+        assert!(!matcher.query("the quick brown dog jumps"));
+    }
+
+    #[test]
+    fn implicit_and()
+    {
+        let matcher = Matcher::from("\"red\" \"car\"").unwrap();
+        print_on_failure(&matcher, "a red car");
+        assert!(!matcher.query("a red bike"));
+    }
 }
@@ -12,10 +12,18 @@
 //! * An AND node matches if *both* its left *and* right children match.
 //! * An OR node matches if *either* its left *or* right children match.
 //! * A NOT node matches if it's child node *doesn't* match.
+//! * A Regex node matches if its compiled pattern is found in the text.
+//! * A Near node matches if its left and right children each have an occurrence within N
+//!   word-tokens of each other.
 
+use std::borrow::Cow;
 use std::collections::VecDeque;
+use std::fmt;
+
+use regex::Regex;
 
 /// Represents a node in the internal string.
+#[derive(Debug)]
 pub enum Node
 {
     AND(Box<Node>, Box<Node>),
@@ -26,22 +34,117 @@ pub enum Node
     ///
     /// String: The keyword to search for.
     /// Vec<i64>: The (precomputed) jump table for the Knuth-Morris-Pratt algorithm.
-    Leaf(String, Vec<i64>)
+    /// usize: The maximum edit distance allowed for a match (0 means an exact match).
+    Leaf(String, Vec<i64>, usize),
+
+    /// A leaf that matches when the compiled pattern is found in the (lowercased) text.
+    Regex(Regex),
+
+    /// A proximity match: matches if the left and right children each have an occurrence
+    /// within `usize` word-tokens of each other.
+    ///
+    /// If either child matches without producing a span (e.g. a NOT, or a compound expression
+    /// that matched via union/negation without a concrete position), there is nothing to measure
+    /// a distance from, so the whole Near fails to match.
+    Near(Box<Node>, Box<Node>, usize),
 }
 
 #[derive(Debug)]
 enum Token
 {
-    AND,
-    OR,
-    NOT,
-    LParen,
-    RParen,
-    Keyword(String),
+    AND(usize),
+    OR(usize),
+    NOT(usize),
+    LParen(usize),
+    RParen(usize),
+    Keyword(String, usize),
+    Regex(String, usize),
+    Near(usize, usize),
+}
+
+/// The byte offset at which a token was found.
+fn token_offset(t: &Token) -> usize
+{
+    use self::Token::*;
+    match t
+    {
+        &AND(offset) => offset,
+        &OR(offset) => offset,
+        &NOT(offset) => offset,
+        &LParen(offset) => offset,
+        &RParen(offset) => offset,
+        &Keyword(_, offset) => offset,
+        &Regex(_, offset) => offset,
+        &Near(_, offset) => offset,
+    }
+}
+
+/// Represents an error in parsing the query: a human-readable reason plus the position in the
+/// query string at which the problem was found.
+#[derive(Debug)]
+pub struct ParsingError
+{
+    reason: Cow<'static, str>,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl ParsingError
+{
+    fn new<S: Into<Cow<'static, str>>>(reason: S, offset: usize) -> Self
+    {
+        // line/column default to the byte offset until `with_position` derives the real
+        // (line, column) from the full query string; callers that never attach a query (e.g.
+        // the tests that exercise the tokeniser/builder directly) still get a sane answer for
+        // single-line, all-ASCII input.
+        ParsingError { reason: reason.into(), offset: offset, line: 1, column: offset + 1 }
+    }
+
+    /// Derives the (line, column) of this error's offset within `query`, counting characters
+    /// (not bytes) so that multi-byte characters don't inflate the column number.
+    fn with_position(mut self, query: &str) -> Self
+    {
+        let (line, column) = line_col(query, self.offset);
+        self.line = line;
+        self.column = column;
+        return self;
+    }
+}
+
+/// Converts a byte offset into `query` into a 1-indexed (line, column) pair, counting
+/// characters rather than bytes so multi-byte characters count as a single column.
+fn line_col(query: &str, byte_offset: usize) -> (usize, usize)
+{
+    let mut line = 1;
+    let mut column = 1;
+
+    for (i, c) in query.char_indices()
+    {
+        if i >= byte_offset { break; }
+
+        if c == '\n' { line += 1; column = 1; }
+        else { column += 1; }
+    }
+
+    return (line, column);
 }
 
-/// Represents an error in parsing the query.
-#[derive(Debug)] pub struct ParsingError(&'static str);
+impl fmt::Display for ParsingError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        if self.line == 1
+        {
+            write!(f, "error at col {}: {}", self.column, self.reason)
+        }
+
+        else
+        {
+            write!(f, "error at line {}, col {}: {}", self.line, self.column, self.reason)
+        }
+    }
+}
 
 // pub enum Result<T>
 // {
@@ -56,29 +159,46 @@ pub type Result<T> = ::std::result::Result<T, ParsingError>;
 /// Returns a ParsingError if the query is malformed.
 pub fn from(s: &str) -> Result<Node>
 {
+    return from_fuzzy(s, 0);
+}
+
+/// Constructs a new query object from string, allowing each leaf keyword to match text
+/// that is within `k` edits (insertions, deletions, substitutions) of the keyword.
+///
+/// A `k` of 0 is equivalent to `from`.
+///
+/// Returns a ParsingError if the query is malformed.
+pub fn from_fuzzy(s: &str, k: usize) -> Result<Node>
+{
+    let eof = s.len();
+
     match tokenise_query(s.to_string())
     {
-        Ok(ts) => return build_bquery(ts),
-        Err(e) => return Err(e),
+        Ok(ts) => return build_bquery(ts, k, eof).map_err(|e| e.with_position(s)),
+        Err(e) => return Err(e.with_position(s)),
     }
 }
 
 fn tokenise_query(query: String) -> Result<VecDeque<Token>>
 {
-    let mut query = query.chars();
+    let mut query = query.char_indices().peekable();
     let mut tokens: VecDeque<Token> = VecDeque::new();
 
     // persistent state
     let mut quotes = false;
+    let mut quote_char = '"';
+    let mut quote_start = 0;
+    let mut in_regex = false;
+    let mut regex_start = 0;
     let mut current_token = String::new();
 
-    while let Some(c) = query.next()
+    while let Some((offset, c)) = query.next()
     {
         if quotes
         {
-            if c == '"'
+            if c == quote_char
             {
-                tokens.push_back(Token::Keyword(current_token));
+                tokens.push_back(Token::Keyword(current_token, quote_start));
                 current_token = String::new();
                 quotes = false;
             }
@@ -86,54 +206,111 @@ fn tokenise_query(query: String) -> Result<VecDeque<Token>>
             else { current_token.push(c); }
         }
 
+        else if in_regex
+        {
+            if c == '\\'
+            {
+                match query.next()
+                {
+                    Some((_, '/')) => current_token.push('/'),
+                    Some((_, other)) => { current_token.push('\\'); current_token.push(other); },
+                    None => return Err(ParsingError::new("unterminated regex", regex_start)),
+                }
+            }
+
+            else if c == '/'
+            {
+                tokens.push_back(Token::Regex(current_token, regex_start));
+                current_token = String::new();
+                in_regex = false;
+            }
+
+            else { current_token.push(c); }
+        }
+
         else
         {
             match c
             {
-                '"' =>
+                '"' | '\'' =>
                 {
                     current_token = String::new();
                     quotes = true;
+                    quote_char = c;
+                    quote_start = offset;
+                },
+
+                '/' =>
+                {
+                    current_token = String::new();
+                    in_regex = true;
+                    regex_start = offset;
                 },
 
                 'a'...'z' | 'A'...'Z' =>
                 {
-                    return Err(ParsingError("Found an alphabetic character when either a quote, parenthesis, or operator was expected"));
+                    return Err(ParsingError::new("found an alphabetic character when either a quote, parenthesis, or operator was expected", offset));
                 },
 
-                '&' => tokens.push_back(Token::AND),
-                '|' => tokens.push_back(Token::OR),
-                '!' => tokens.push_back(Token::NOT),
-                '(' => tokens.push_back(Token::LParen),
-                ')' => tokens.push_back(Token::RParen),
+                '&' => tokens.push_back(Token::AND(offset)),
+                '|' => tokens.push_back(Token::OR(offset)),
+                '!' => tokens.push_back(Token::NOT(offset)),
+                '(' => tokens.push_back(Token::LParen(offset)),
+                ')' => tokens.push_back(Token::RParen(offset)),
+
+                '~' =>
+                {
+                    let mut digits = String::new();
+                    while let Some(&(_, d)) = query.peek()
+                    {
+                        if d.is_digit(10) { digits.push(d); query.next(); }
+                        else { break; }
+                    }
+
+                    match digits.parse::<usize>()
+                    {
+                        Ok(n) => tokens.push_back(Token::Near(n, offset)),
+                        Err(_) => return Err(ParsingError::new("expected a number after '~'", offset)),
+                    }
+                },
 
                 // skip whitespace
                 ' ' | '\t' | '\n' | '\r' => continue,
 
-                _ => return Err(ParsingError("found an unexpected character")),
+                _ => return Err(ParsingError::new("found an unexpected character", offset)),
             }
         }
     }
 
+    if quotes
+    {
+        return Err(ParsingError::new("unterminated quote", quote_start));
+    }
+
+    if in_regex
+    {
+        return Err(ParsingError::new("unterminated regex", regex_start));
+    }
+
     return Ok(tokens);
 }
 
-fn build_bquery(mut tokens: VecDeque<Token>) -> Result<Node>
+fn build_bquery(mut tokens: VecDeque<Token>, k: usize, eof: usize) -> Result<Node>
 {
-    return build_query(&mut tokens);
+    return build_query(&mut tokens, k, eof);
 }
 
-fn build_query(tokens: &mut VecDeque<Token>) -> Result<Node>
+fn build_query(tokens: &mut VecDeque<Token>, k: usize, eof: usize) -> Result<Node>
 {
-    match build_or_group(tokens)
+    match build_or_group(tokens, k, eof)
     {
         Ok(mut left) =>
         {
             while let Some(t) = tokens.pop_front()
             {
-                if let Token::OR = t
+                if let Token::OR(_) = t
                 {
-                    match build_or_group(tokens)
+                    match build_or_group(tokens, k, eof)
                     {
                         Ok(mut right) => left = Node::OR(Box::new(left), Box::new(right)),
                         Err(e) => return Err(e),
@@ -154,17 +331,17 @@ fn build_query(tokens: &mut VecDeque<Token>) -> Result<Node>
     }
 }
 
-fn build_or_group(tokens: &mut VecDeque<Token>) -> Result<Node>
+fn build_or_group(tokens: &mut VecDeque<Token>, k: usize, eof: usize) -> Result<Node>
 {
-    match build_and_group(tokens)
+    match build_and_group(tokens, k, eof)
     {
         Ok(mut left) =>
         {
             while let Some(t) = tokens.pop_front()
             {
-                if let Token::AND = t
+                if let Token::AND(_) = t
                 {
-                    match build_and_group(tokens)
+                    match build_and_group(tokens, k, eof)
                     {
                         Ok(mut right) => left = Node::AND(Box::new(left), Box::new(right)),
                         Err(e) => return Err(e),
@@ -185,7 +362,89 @@ fn build_or_group(tokens: &mut VecDeque<Token>) -> Result<Node>
     }
 }
 
-fn build_and_group(tokens: &mut VecDeque<Token>) -> Result<Node>
+fn build_and_group(tokens: &mut VecDeque<Token>, k: usize, eof: usize) -> Result<Node>
+{
+    match build_near_group(tokens, k, eof)
+    {
+        Ok(mut left) =>
+        {
+            while let Some(t) = tokens.pop_front()
+            {
+                // an explicit `&`, or two term-producing tokens sitting next to each other
+                // with no operator between them, both combine via AND - "red" "car" parses
+                // the same as "red" & "car".
+                let implicit_and = match t
+                {
+                    Token::Keyword(_, _) | Token::Regex(_, _) | Token::LParen(_) => true,
+                    _ => false,
+                };
+
+                if let Token::AND(_) = t
+                {
+                    match build_near_group(tokens, k, eof)
+                    {
+                        Ok(right) => left = Node::AND(Box::new(left), Box::new(right)),
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                else if implicit_and
+                {
+                    tokens.push_front(t);
+
+                    match build_near_group(tokens, k, eof)
+                    {
+                        Ok(right) => left = Node::AND(Box::new(left), Box::new(right)),
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                else
+                {
+                    tokens.push_front(t);
+                    return Ok(left);
+                }
+            }
+
+            return Ok(left);
+        },
+
+        Err(e) => return Err(e),
+    }
+}
+
+fn build_near_group(tokens: &mut VecDeque<Token>, k: usize, eof: usize) -> Result<Node>
+{
+    match build_atom(tokens, k, eof)
+    {
+        Ok(mut left) =>
+        {
+            while let Some(t) = tokens.pop_front()
+            {
+                if let Token::Near(n, _) = t
+                {
+                    match build_atom(tokens, k, eof)
+                    {
+                        Ok(right) => left = Node::Near(Box::new(left), Box::new(right), n),
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                else
+                {
+                    tokens.push_front(t);
+                    return Ok(left);
+                }
+            }
+
+            return Ok(left);
+        },
+
+        Err(e) => return Err(e),
+    }
+}
+
+fn build_atom(tokens: &mut VecDeque<Token>, k: usize, eof: usize) -> Result<Node>
 {
     use self::Token::*;
 
@@ -193,47 +452,53 @@ fn build_and_group(tokens: &mut VecDeque<Token>) -> Result<Node>
     {
         match t
         {
-            NOT =>
+            NOT(_) =>
             {
-                match build_and_group(tokens)
+                match build_atom(tokens, k, eof)
                 {
                     Ok(node) => return Ok(Node::NOT(Box::new(node))),
                     Err(e) => return Err(e),
                 }
             },
 
-            Keyword(s) =>
+            Keyword(s, _) =>
             {
                 let table = kmp_table(&s.clone().into_bytes());
-                return Ok(Node::Leaf(s, table));
+                return Ok(Node::Leaf(s, table, k));
+            },
+
+            Regex(pattern, offset) =>
+            {
+                match self::Regex::new(&pattern)
+                {
+                    Ok(re) => return Ok(Node::Regex(re)),
+                    Err(_) => return Err(ParsingError::new("invalid regular expression", offset)),
+                }
             },
 
-            LParen =>
+            LParen(_) =>
             {
-                let expr = match build_query(tokens)
+                let expr = match build_query(tokens, k, eof)
                 {
                     Ok(expr) => expr,
                     Err(e) => return Err(e),
                 };
 
-                if let Some(Token::RParen) = tokens.pop_front()
+                match tokens.pop_front()
                 {
-                    return Ok(expr);
-                }
-
-                else
-                {
-                    return Err(ParsingError("Expected closing parentheses after expression"));
+                    Some(Token::RParen(_)) => return Ok(expr),
+                    Some(other) => return Err(ParsingError::new("expected closing parenthesis after expression", token_offset(&other))),
+                    None => return Err(ParsingError::new("expected closing parenthesis after expression", eof)),
                 }
             },
 
-            _ => return Err(ParsingError("Unexpected token ")),
+            other => return Err(ParsingError::new("unexpected token", token_offset(&other))),
         }
     }
 
     else
     {
-        return Err(ParsingError("Unexpected end of input"));
+        return Err(ParsingError::new("unexpected end of input", eof));
     }
 }
 
@@ -280,7 +545,8 @@ mod tests
 
     fn do_both(s: String)
     {
-        build_bquery(tokenise_query(s).unwrap()).unwrap();
+        let eof = s.len();
+        build_bquery(tokenise_query(s).unwrap(), 0, eof).unwrap();
     }
 
     #[test]
@@ -301,4 +567,54 @@ mod tests
     {
         do_both("\"iphone\" | \"i phone\"".to_string());
     }
+
+    #[test]
+    fn regex_leaf()
+    {
+        do_both("/iph[o0]ne/".to_string());
+    }
+
+    #[test]
+    fn near_operator()
+    {
+        do_both("\"quick\" ~3 \"fox\"".to_string());
+    }
+
+    #[test]
+    fn single_quotes()
+    {
+        do_both("'hello'".to_string());
+    }
+
+    #[test]
+    fn implicit_and()
+    {
+        do_both("\"red\" \"car\"".to_string());
+    }
+
+    #[test]
+    fn error_location()
+    {
+        let err = from("\"iphone\" &").unwrap_err();
+
+        assert_eq!(format!("{}", err), "error at col 11: unexpected end of input");
+    }
+
+    #[test]
+    fn error_location_multibyte()
+    {
+        // "café" is 6 characters but 7 bytes (é is 2 bytes in UTF-8); the column reported
+        // for the offending '@' must count characters, not bytes.
+        let err = from("\"café\" @").unwrap_err();
+
+        assert_eq!(format!("{}", err), "error at col 8: found an unexpected character");
+    }
+
+    #[test]
+    fn error_location_multiline()
+    {
+        let err = from("\"iphone\"\n&").unwrap_err();
+
+        assert_eq!(format!("{}", err), "error at line 2, col 2: unexpected end of input");
+    }
 }